@@ -1,13 +1,16 @@
 //! This module offers a high level synchronous abstraction to async eventloop.
 //! Uses channels internally to get `Requests` and send `Notifications`
-use crate::{ConnectionError, Event, EventLoop, MqttOptions, Request};
+use crate::state::PkidCounter;
+use crate::{ConnectionError, Event, EventLoop, MqttOptions, ProtocolError, Request};
 
-use async_channel::{SendError, Sender};
+use async_channel::{Receiver, SendError, Sender, TrySendError};
 use mqtt4bytes::*;
 use std::mem;
+use std::thread;
 use std::time::Duration;
 use tokio::runtime;
 use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
 
 /// Client Error
 #[derive(Debug, thiserror::Error)]
@@ -18,12 +21,23 @@ pub enum ClientError {
     Request(#[from] SendError<Request>),
     #[error("Serialization error")]
     Mqtt4(mqtt4bytes::Error),
+    #[error("Failed to send mqtt request, queue full or closed")]
+    TrySend(#[from] TrySendError<Request>),
+    #[error("subscribe_many called with no topic filters")]
+    EmptySubscribeMany,
 }
 
+/// Resolves once the broker has acknowledged the packet it was handed out
+/// for: PUBACK/PUBCOMP for a QoS 1/2 publish, SUBACK for a subscribe. Can
+/// also resolve to `Err` if the eventloop rejects the request outright
+/// (e.g. an oversized payload) before it ever reaches the network.
+pub type AckFuture = oneshot::Receiver<Result<(), ProtocolError>>;
+
 #[derive(Clone)]
 pub struct AsyncClient {
     request_tx: Sender<Request>,
     cancel_tx: Sender<()>,
+    pkids: PkidCounter,
 }
 
 impl AsyncClient {
@@ -31,10 +45,12 @@ impl AsyncClient {
         let mut eventloop = EventLoop::new(options, cap);
         let request_tx = eventloop.handle();
         let cancel_tx = eventloop.take_cancel_handle().unwrap();
+        let pkids = eventloop.pkid_handle();
 
         let client = AsyncClient {
             request_tx,
             cancel_tx,
+            pkids,
         };
 
         (client, eventloop)
@@ -54,23 +70,162 @@ impl AsyncClient {
     {
         let mut publish = Publish::new(topic, qos, payload);
         publish.retain = retain;
+        if qos != QoS::AtMostOnce {
+            publish.pkid = self.pkids.next();
+        }
         let publish = Request::Publish(publish);
         self.request_tx.send(publish).await?;
         Ok(())
     }
 
+    /// Sends a MQTT Publish to the eventloop, returning the packet
+    /// identifier the eventloop will tag it with and a future that
+    /// resolves once the broker has acknowledged it (PUBACK for QoS 1,
+    /// PUBCOMP for QoS 2). For QoS 0 there's no packet identifier (the
+    /// returned pkid is 0, matching `publish`/`try_publish`) and the
+    /// future resolves immediately since there's no broker acknowledgement
+    /// to wait for.
+    pub async fn publish_with_ack<S, V>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<(u16, AckFuture), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let mut publish = Publish::new(topic, qos, payload);
+        publish.retain = retain;
+        if qos != QoS::AtMostOnce {
+            publish.pkid = self.pkids.next();
+        }
+        let pkid = publish.pkid;
+
+        let (tx, rx) = oneshot::channel();
+        if qos == QoS::AtMostOnce {
+            // No pkid and no ack coming for QoS 0, resolve right away and
+            // send a plain Publish since there's no ack for the eventloop
+            // to register the sender against.
+            let _ = tx.send(Ok(()));
+            self.request_tx.send(Request::Publish(publish)).await?;
+        } else {
+            self.request_tx
+                .send(Request::PublishWithAck(publish, tx))
+                .await?;
+        }
+        Ok((pkid, rx))
+    }
+
+    /// Non-blocking version of `publish` for back-pressure-sensitive
+    /// producers (e.g. a sensor loop) that would rather handle a full
+    /// queue themselves than suspend. Returns the packet back to the
+    /// caller (inside the `TrySendError`) if it couldn't be enqueued.
+    pub fn try_publish<S, V>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let mut publish = Publish::new(topic, qos, payload);
+        publish.retain = retain;
+        if qos != QoS::AtMostOnce {
+            publish.pkid = self.pkids.next();
+        }
+        self.request_tx.try_send(Request::Publish(publish))?;
+        Ok(())
+    }
+
     /// Sends a MQTT Subscribe to the eventloop
     pub async fn subscribe<S: Into<String>>(
         &mut self,
         topic: S,
         qos: QoS,
     ) -> Result<(), ClientError> {
-        let subscribe = Subscribe::new(topic.into(), qos);
+        let mut subscribe = Subscribe::new(topic.into(), qos);
+        subscribe.pkid = self.pkids.next();
         let request = Request::Subscribe(subscribe);
         self.request_tx.send(request).await?;
         Ok(())
     }
 
+    /// Sends a MQTT Subscribe to the eventloop, returning the packet
+    /// identifier and a future that resolves once the matching SUBACK
+    /// arrives.
+    pub async fn subscribe_with_ack<S: Into<String>>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<(u16, AckFuture), ClientError> {
+        let mut subscribe = Subscribe::new(topic.into(), qos);
+        subscribe.pkid = self.pkids.next();
+        let pkid = subscribe.pkid;
+
+        let (tx, rx) = oneshot::channel();
+        self.request_tx
+            .send(Request::SubscribeWithAck(subscribe, tx))
+            .await?;
+        Ok((pkid, rx))
+    }
+
+    /// Non-blocking version of `subscribe`. Returns the packet back to the
+    /// caller (inside the `TrySendError`) if the request queue is full or
+    /// closed.
+    pub fn try_subscribe<S: Into<String>>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+    ) -> Result<(), ClientError> {
+        let mut subscribe = Subscribe::new(topic.into(), qos);
+        subscribe.pkid = self.pkids.next();
+        self.request_tx.try_send(Request::Subscribe(subscribe))?;
+        Ok(())
+    }
+
+    /// Subscribes to several topic filters in a single MQTT Subscribe
+    /// packet, instead of sending one packet per filter.
+    pub async fn subscribe_many<T>(&mut self, topics: T) -> Result<(), ClientError>
+    where
+        T: IntoIterator<Item = SubscribeTopic>,
+    {
+        let topics: Vec<SubscribeTopic> = topics.into_iter().collect();
+        if topics.is_empty() {
+            return Err(ClientError::EmptySubscribeMany);
+        }
+
+        let subscribe = Subscribe {
+            pkid: self.pkids.next(),
+            topics,
+        };
+        self.request_tx.send(Request::Subscribe(subscribe)).await?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Unsubscribe to the eventloop
+    pub async fn unsubscribe<S: Into<String>>(&mut self, topic: S) -> Result<(), ClientError> {
+        let mut unsubscribe = Unsubscribe::new(topic.into());
+        unsubscribe.pkid = self.pkids.next();
+        self.request_tx
+            .send(Request::Unsubscribe(unsubscribe))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a MQTT DISCONNECT, letting the eventloop flush it to the
+    /// broker before shutting down. Unlike `cancel()`, which drops the TCP
+    /// connection without warning and triggers the broker's Last Will,
+    /// this suppresses the will and gives deterministic shutdown semantics.
+    pub async fn disconnect(&mut self) -> Result<(), ClientError> {
+        self.request_tx.send(Request::Disconnect).await?;
+        Ok(())
+    }
+
     /// Stops the eventloop right away
     pub async fn cancel(&mut self) -> Result<(), ClientError> {
         self.cancel_tx.send(()).await?;
@@ -102,6 +257,39 @@ impl Client {
         (client, connection)
     }
 
+    /// Spawns the eventloop on its own background thread instead of
+    /// requiring the caller to drive `Connection::iter()`, and hands back
+    /// a cloneable `Client` plus a `Receiver` of every `Event` the
+    /// eventloop produces. Lets an app both publish/subscribe and consume
+    /// notifications without juggling an iterator on the same thread.
+    ///
+    /// `reconnection_delay` is the delay between automatic reconnection
+    /// attempts, equivalent to `EventLoop::set_reconnection_delay`, which
+    /// can't be called by the caller once the eventloop moves into the
+    /// background thread.
+    pub fn background(
+        options: MqttOptions,
+        cap: usize,
+        reconnection_delay: Duration,
+    ) -> (Client, Receiver<Result<Event, ConnectionError>>) {
+        let (client, mut eventloop) = AsyncClient::new(options, cap);
+        let client = Client { client };
+        eventloop.set_reconnection_delay(reconnection_delay);
+
+        let (notification_tx, notification_rx) = async_channel::bounded(cap);
+        thread::spawn(move || {
+            let runtime = runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(background_loop(eventloop, notification_tx));
+        });
+
+        (client, notification_rx)
+    }
+
     /// Sends a MQTT Publish to the eventloop
     pub fn publish<S, V>(
         &mut self,
@@ -118,12 +306,55 @@ impl Client {
         Ok(())
     }
 
+    /// Non-blocking version of `publish`. See `AsyncClient::try_publish`
+    pub fn try_publish<S, V>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        self.client.try_publish(topic, qos, retain, payload)
+    }
+
     /// Sends a MQTT Subscribe to the eventloop
     pub fn subscribe<S: Into<String>>(&mut self, topic: S, qos: QoS) -> Result<(), ClientError> {
         pollster::block_on(self.client.subscribe(topic, qos))?;
         Ok(())
     }
 
+    /// Non-blocking version of `subscribe`. See `AsyncClient::try_subscribe`
+    pub fn try_subscribe<S: Into<String>>(&mut self, topic: S, qos: QoS) -> Result<(), ClientError> {
+        self.client.try_subscribe(topic, qos)
+    }
+
+    /// Subscribes to several topic filters in a single MQTT Subscribe
+    /// packet. See `AsyncClient::subscribe_many`
+    pub fn subscribe_many<T>(&mut self, topics: T) -> Result<(), ClientError>
+    where
+        T: IntoIterator<Item = SubscribeTopic>,
+    {
+        pollster::block_on(self.client.subscribe_many(topics))?;
+        Ok(())
+    }
+
+    /// Sends a MQTT Unsubscribe to the eventloop
+    pub fn unsubscribe<S: Into<String>>(&mut self, topic: S) -> Result<(), ClientError> {
+        pollster::block_on(self.client.unsubscribe(topic))?;
+        Ok(())
+    }
+
+    /// Sends a MQTT DISCONNECT and lets the eventloop shut down cleanly,
+    /// suppressing any configured Last Will
+    pub fn disconnect(&mut self) -> Result<(), ClientError> {
+        pollster::block_on(self.client.disconnect())?;
+        Ok(())
+    }
+
     /// Stops the eventloop right away
     pub fn cancel(&mut self) -> Result<(), ClientError> {
         pollster::block_on(self.client.cancel())?;
@@ -131,11 +362,44 @@ impl Client {
     }
 }
 
+/// Whether `result` should stop `background_loop`: the requests channel
+/// closed, or the user cancelled/disconnected. Everything else (connect
+/// retries, client errors) keeps the loop going.
+fn is_terminal(result: &Result<Event, ConnectionError>) -> bool {
+    matches!(
+        result,
+        Err(ConnectionError::RequestsDone)
+            | Err(ConnectionError::Cancel)
+            | Err(ConnectionError::Disconnect)
+    )
+}
+
+/// Drives `eventloop` to completion, forwarding every `Event`/error onto
+/// `notifications`. Terminates once the eventloop reports the requests
+/// channel closed or a user cancellation/disconnect.
+async fn background_loop(
+    mut eventloop: EventLoop,
+    notifications: Sender<Result<Event, ConnectionError>>,
+) {
+    loop {
+        let result = eventloop.poll().await;
+        let done = is_terminal(&result);
+
+        if notifications.send(result).await.is_err() || done {
+            break;
+        }
+    }
+}
+
 ///  MQTT connection. Maintains all the necessary state and automatically retries connections
 /// in flaky networks.
 pub struct Connection {
     pub eventloop: EventLoop,
     runtime: Option<Runtime>,
+    /// When `true`, a `ConnectionError::Client` (protocol/encode fault,
+    /// as opposed to a retried `ConnectionError::Connect`) stops the
+    /// iterator instead of being yielded and skipped over.
+    fail_fast_on_client_errors: bool,
 }
 
 impl Connection {
@@ -144,6 +408,7 @@ impl Connection {
         Connection {
             eventloop,
             runtime: Some(runtime),
+            fail_fast_on_client_errors: false,
         }
     }
 
@@ -152,6 +417,14 @@ impl Connection {
         self.eventloop.set_reconnection_delay(delay)
     }
 
+    /// Choose how the iterator reacts to a `ConnectionError::Client`
+    /// (protocol/encode faults that auto-reconnect can't fix): `true` stops
+    /// iteration on the first one (fail-fast), `false` (the default)
+    /// yields it and keeps going (resilient).
+    pub fn set_fail_fast_on_client_errors(&mut self, fail_fast: bool) {
+        self.fail_fast_on_client_errors = fail_fast;
+    }
+
     /// Returns an iterator over this connection. Iterating over this is all that's
     /// necessary to make connection progress and maintain a robust connection
     /// **NOTE** Don't block this
@@ -161,6 +434,7 @@ impl Connection {
         Iter {
             connection: self,
             runtime,
+            halted: false,
         }
     }
 }
@@ -169,12 +443,17 @@ impl Connection {
 pub struct Iter<'a> {
     connection: &'a mut Connection,
     runtime: runtime::Runtime,
+    halted: bool,
 }
 
 impl<'a> Iterator for Iter<'a> {
     type Item = Result<Event, ConnectionError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.halted {
+            return None;
+        }
+
         let f = self.connection.eventloop.poll();
         match self.runtime.block_on(f) {
             Ok(v) => Some(Ok(v)),
@@ -187,6 +466,14 @@ impl<'a> Iterator for Iter<'a> {
                 trace!("Cancellation request received");
                 None
             }
+            Err(ConnectionError::Disconnect) => {
+                trace!("Gracefully disconnected");
+                None
+            }
+            Err(e @ ConnectionError::Client(_)) if self.connection.fail_fast_on_client_errors => {
+                self.halted = true;
+                Some(Err(e))
+            }
             Err(e) => Some(Err(e)),
         }
     }
@@ -199,3 +486,185 @@ impl<'a> Drop for Iter<'a> {
         self.connection.runtime = Some(mem::replace(&mut self.runtime, runtime));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConnectError, ProtocolError};
+
+    /// An `AsyncClient` wired to a request channel we can inspect directly,
+    /// without spinning up an `EventLoop`/network connection.
+    fn test_client() -> (AsyncClient, Receiver<Request>) {
+        let (request_tx, request_rx) = async_channel::bounded(10);
+        let (cancel_tx, _cancel_rx) = async_channel::bounded(1);
+        let client = AsyncClient {
+            request_tx,
+            cancel_tx,
+            pkids: PkidCounter::default(),
+        };
+        (client, request_rx)
+    }
+
+    #[tokio::test]
+    async fn publish_assigns_pkid_for_qos_above_zero() {
+        let (mut client, request_rx) = test_client();
+        client
+            .publish("topic", QoS::AtLeastOnce, false, vec![1, 2, 3])
+            .await
+            .unwrap();
+
+        match request_rx.recv().await.unwrap() {
+            Request::Publish(publish) => assert_ne!(publish.pkid, 0),
+            other => panic!("expected Request::Publish, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_leaves_pkid_unset_for_qos_zero() {
+        let (mut client, request_rx) = test_client();
+        client
+            .publish("topic", QoS::AtMostOnce, false, vec![1])
+            .await
+            .unwrap();
+
+        match request_rx.recv().await.unwrap() {
+            Request::Publish(publish) => assert_eq!(publish.pkid, 0),
+            other => panic!("expected Request::Publish, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn try_publish_assigns_pkid_for_qos_above_zero() {
+        let (mut client, request_rx) = test_client();
+        client
+            .try_publish("topic", QoS::ExactlyOnce, false, vec![1])
+            .unwrap();
+
+        match request_rx.recv().await.unwrap() {
+            Request::Publish(publish) => assert_ne!(publish.pkid, 0),
+            other => panic!("expected Request::Publish, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_assigns_nonzero_pkid() {
+        let (mut client, request_rx) = test_client();
+        client.subscribe("topic", QoS::AtLeastOnce).await.unwrap();
+
+        match request_rx.recv().await.unwrap() {
+            Request::Subscribe(subscribe) => assert_ne!(subscribe.pkid, 0),
+            other => panic!("expected Request::Subscribe, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_many_assigns_nonzero_pkid() {
+        let (mut client, request_rx) = test_client();
+        client
+            .subscribe_many(vec![SubscribeTopic {
+                topic: "topic".into(),
+                qos: QoS::AtLeastOnce,
+            }])
+            .await
+            .unwrap();
+
+        match request_rx.recv().await.unwrap() {
+            Request::Subscribe(subscribe) => assert_ne!(subscribe.pkid, 0),
+            other => panic!("expected Request::Subscribe, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_many_rejects_empty_topics() {
+        let (mut client, request_rx) = test_client();
+
+        let err = client
+            .subscribe_many(Vec::<SubscribeTopic>::new())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, ClientError::EmptySubscribeMany));
+        assert!(request_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_assigns_nonzero_pkid() {
+        let (mut client, request_rx) = test_client();
+        client.unsubscribe("topic").await.unwrap();
+
+        match request_rx.recv().await.unwrap() {
+            Request::Unsubscribe(unsubscribe) => assert_ne!(unsubscribe.pkid, 0),
+            other => panic!("expected Request::Unsubscribe, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_with_ack_resolves_immediately_for_qos_zero() {
+        let (mut client, request_rx) = test_client();
+        let (pkid, mut ack) = client
+            .publish_with_ack("topic", QoS::AtMostOnce, false, vec![1])
+            .await
+            .unwrap();
+
+        assert_eq!(pkid, 0);
+        assert!(matches!(ack.try_recv(), Ok(Ok(()))));
+
+        match request_rx.recv().await.unwrap() {
+            Request::Publish(publish) => assert_eq!(publish.pkid, 0),
+            other => panic!("expected Request::Publish, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_with_ack_registers_pending_ack_for_qos_above_zero() {
+        let (mut client, request_rx) = test_client();
+        let (pkid, mut ack) = client
+            .publish_with_ack("topic", QoS::AtLeastOnce, false, vec![1])
+            .await
+            .unwrap();
+
+        assert_ne!(pkid, 0);
+        assert!(ack.try_recv().is_err());
+
+        match request_rx.recv().await.unwrap() {
+            Request::PublishWithAck(publish, _) => assert_eq!(publish.pkid, pkid),
+            other => panic!("expected Request::PublishWithAck, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_with_ack_registers_pending_ack() {
+        let (mut client, request_rx) = test_client();
+        let (pkid, mut ack) = client
+            .subscribe_with_ack("topic", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+
+        assert_ne!(pkid, 0);
+        assert!(ack.try_recv().is_err());
+
+        match request_rx.recv().await.unwrap() {
+            Request::SubscribeWithAck(subscribe, _) => assert_eq!(subscribe.pkid, pkid),
+            other => panic!("expected Request::SubscribeWithAck, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn background_loop_stops_on_requests_done_cancel_and_disconnect() {
+        assert!(is_terminal(&Err(ConnectionError::RequestsDone)));
+        assert!(is_terminal(&Err(ConnectionError::Cancel)));
+        assert!(is_terminal(&Err(ConnectionError::Disconnect)));
+    }
+
+    #[test]
+    fn background_loop_keeps_going_on_connect_and_client_errors() {
+        let connect_err = ConnectionError::Connect(ConnectError::Io(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "connection reset",
+        )));
+        assert!(!is_terminal(&Err(connect_err)));
+
+        let client_err = ConnectionError::Client(ProtocolError::PayloadTooLong(1));
+        assert!(!is_terminal(&Err(client_err)));
+    }
+}
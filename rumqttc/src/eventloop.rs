@@ -0,0 +1,316 @@
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender};
+use mqtt4bytes::*;
+use tokio::time;
+
+use crate::network::Network;
+use crate::state::{MqttState, PkidCounter};
+use crate::{MqttOptions, Request};
+
+/// Events yielded by [`EventLoop::poll`] for every incoming/outgoing packet
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Incoming(Packet),
+    Outgoing(Outgoing),
+}
+
+/// A marker of what the eventloop just wrote to the network, since the
+/// encoded packet itself isn't handed back to the caller
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outgoing {
+    Publish(u16),
+    Subscribe(u16),
+    Unsubscribe(u16),
+    PingReq,
+}
+
+/// Maximum publish payload size accepted, per the MQTT v3.1.1 remaining
+/// length encoding
+const MAX_PAYLOAD_SIZE: usize = 268_435_455;
+
+/// Transient, network-level failures: DNS/TCP/TLS setup, a dropped socket,
+/// or the broker refusing the CONNECT. All of these are retried by the
+/// eventloop's auto-reconnect after `reconnection_delay`.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("Io error {0:?}")]
+    Io(#[from] std::io::Error),
+    #[error("Connection refused, return code = {0:?}")]
+    ConnectionRefused(ConnectReturnCode),
+    #[error("Expected ConnAck packet, received: {0:?}")]
+    NotConnAck(Packet),
+}
+
+/// Faults in our own usage of the protocol: a packet that fails to encode
+/// or an oversized payload. These don't go away
+/// on retry, so they're not handled by auto-reconnect.
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("Failed to encode/write packet: {0:?}")]
+    Write(std::io::Error),
+    #[error("Payload size {0} bytes exceeds the {} byte limit", MAX_PAYLOAD_SIZE)]
+    PayloadTooLong(usize),
+}
+
+/// Error type returned by the eventloop. Connection-level failures
+/// (`Connect`) are distinguished from client/protocol-level ones
+/// (`Client`) so a consumer can retry the former and act on the latter.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectionError {
+    #[error("Connection error: {0}")]
+    Connect(#[from] ConnectError),
+    #[error("Client error: {0}")]
+    Client(#[from] ProtocolError),
+    #[error("Requests done")]
+    RequestsDone,
+    #[error("Cancelled by the user")]
+    Cancel,
+    #[error("Gracefully disconnected")]
+    Disconnect,
+}
+
+impl ConnectionError {
+    /// Whether this is a connection-level error that the eventloop's
+    /// auto-reconnect will retry, as opposed to a client/protocol error
+    pub fn is_connection_error(&self) -> bool {
+        matches!(self, ConnectionError::Connect(_))
+    }
+}
+
+// `Network::write` reports an encode failure as `InvalidData`; any other
+// error came from the socket itself and is a connection-level failure.
+fn write_error(e: std::io::Error) -> ConnectionError {
+    match e.kind() {
+        std::io::ErrorKind::InvalidData => ProtocolError::Write(e).into(),
+        _ => ConnectError::Io(e).into(),
+    }
+}
+
+/// Drives the connection to a single mqtt broker. Reconnects (after
+/// `reconnection_delay`) on any network-level error and keeps serving
+/// requests from `request_rx`.
+pub struct EventLoop {
+    pub options: MqttOptions,
+    pub(crate) state: MqttState,
+    request_tx: Sender<Request>,
+    request_rx: Receiver<Request>,
+    cancel_tx: Option<Sender<()>>,
+    cancel_rx: Receiver<()>,
+    network: Option<Network>,
+    reconnection_delay: Duration,
+}
+
+impl EventLoop {
+    pub fn new(options: MqttOptions, cap: usize) -> EventLoop {
+        let (request_tx, request_rx) = async_channel::bounded(cap);
+        let (cancel_tx, cancel_rx) = async_channel::bounded(5);
+
+        EventLoop {
+            options,
+            state: MqttState::new(),
+            request_tx,
+            request_rx,
+            cancel_tx: Some(cancel_tx),
+            cancel_rx,
+            network: None,
+            reconnection_delay: Duration::from_secs(0),
+        }
+    }
+
+    /// Handle to send requests to this eventloop
+    pub fn handle(&self) -> Sender<Request> {
+        self.request_tx.clone()
+    }
+
+    pub(crate) fn take_cancel_handle(&mut self) -> Option<Sender<()>> {
+        self.cancel_tx.take()
+    }
+
+    /// Shared counter used to stamp pkids on packets before they're even
+    /// sent to the eventloop, so `AsyncClient::publish_with_ack` can hand
+    /// the caller a pkid synchronously.
+    pub(crate) fn pkid_handle(&self) -> PkidCounter {
+        self.state.pkids.clone()
+    }
+
+    /// Set delay between (automatic) re-connections (on error)
+    pub fn set_reconnection_delay(&mut self, delay: Duration) {
+        self.reconnection_delay = delay;
+    }
+
+    async fn connect(&mut self) -> Result<Packet, ConnectError> {
+        let mut network = Network::connect(&self.options).await?;
+
+        let mut connect = Connect::new(self.options.client_id());
+        connect.clean_session = self.options.clean_session();
+        connect.last_will = self.options.last_will();
+        network.write(connect).await?;
+
+        let packet = network.read().await?;
+        match &packet {
+            Packet::ConnAck(connack) if connack.code == ConnectReturnCode::Success => {
+                self.state.clear_acks();
+                self.network = Some(network);
+                Ok(packet)
+            }
+            Packet::ConnAck(connack) => Err(ConnectError::ConnectionRefused(connack.code)),
+            _ => Err(ConnectError::NotConnAck(packet)),
+        }
+    }
+
+    /// Drives the eventloop forward by one step: either a request from the
+    /// client is turned into an outgoing packet, or an incoming packet from
+    /// the broker is handled. Reconnects transparently on network errors.
+    pub async fn poll(&mut self) -> Result<Event, ConnectionError> {
+        if self.network.is_none() {
+            if let Err(e) = self.connect().await {
+                time::sleep(self.reconnection_delay).await;
+                return Err(ConnectionError::Connect(e));
+            }
+        }
+
+        let network = self.network.as_mut().unwrap();
+        tokio::select! {
+            packet = network.read() => {
+                let packet = match packet {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        self.network = None;
+                        time::sleep(self.reconnection_delay).await;
+                        return Err(ConnectionError::Connect(ConnectError::Io(e)));
+                    }
+                };
+
+                match &packet {
+                    Packet::PubAck(puback) => self.state.handle_ack(puback.pkid),
+                    Packet::PubComp(pubcomp) => self.state.handle_ack(pubcomp.pkid),
+                    Packet::SubAck(suback) => self.state.handle_ack(suback.pkid),
+                    _ => {}
+                }
+
+                // QoS 2: the broker only sends PUBCOMP once it has our PUBREL
+                if let Packet::PubRec(pubrec) = &packet {
+                    network.write(PubRel::new(pubrec.pkid)).await.map_err(write_error)?;
+                }
+
+                Ok(Event::Incoming(packet))
+            }
+            request = self.request_rx.recv() => {
+                let request = request.map_err(|_| ConnectionError::RequestsDone)?;
+                self.handle_request(request).await
+            }
+            _ = self.cancel_rx.recv() => {
+                Err(ConnectionError::Cancel)
+            }
+        }
+    }
+
+    async fn handle_request(&mut self, request: Request) -> Result<Event, ConnectionError> {
+        let oversized_payload = match &request {
+            Request::Publish(Publish { payload, .. })
+            | Request::PublishWithAck(Publish { payload, .. }, _)
+                if payload.len() > MAX_PAYLOAD_SIZE =>
+            {
+                Some(payload.len())
+            }
+            _ => None,
+        };
+
+        if let Some(len) = oversized_payload {
+            // Reject before ever touching the network. A waiting
+            // `AckFuture` gets told why instead of just seeing its sender
+            // dropped.
+            if let Request::PublishWithAck(_, notify) = request {
+                let _ = notify.send(Err(ProtocolError::PayloadTooLong(len)));
+            }
+            return Err(ProtocolError::PayloadTooLong(len).into());
+        }
+
+        let network = self.network.as_mut().unwrap();
+        match request {
+            Request::Publish(publish) => {
+                let pkid = publish.pkid;
+                network.write(publish).await.map_err(write_error)?;
+                Ok(Event::Outgoing(Outgoing::Publish(pkid)))
+            }
+            Request::PublishWithAck(publish, notify) => {
+                let pkid = publish.pkid;
+                self.state.register_ack(pkid, notify);
+                network.write(publish).await.map_err(write_error)?;
+                Ok(Event::Outgoing(Outgoing::Publish(pkid)))
+            }
+            Request::Subscribe(subscribe) => {
+                let pkid = subscribe.pkid;
+                network.write(subscribe).await.map_err(write_error)?;
+                Ok(Event::Outgoing(Outgoing::Subscribe(pkid)))
+            }
+            Request::SubscribeWithAck(subscribe, notify) => {
+                let pkid = subscribe.pkid;
+                self.state.register_ack(pkid, notify);
+                network.write(subscribe).await.map_err(write_error)?;
+                Ok(Event::Outgoing(Outgoing::Subscribe(pkid)))
+            }
+            Request::Unsubscribe(unsubscribe) => {
+                let pkid = unsubscribe.pkid;
+                network.write(unsubscribe).await.map_err(write_error)?;
+                Ok(Event::Outgoing(Outgoing::Unsubscribe(pkid)))
+            }
+            Request::Disconnect => {
+                // Flush a proper DISCONNECT so the broker suppresses our
+                // last will, then stop the eventloop cleanly.
+                network.write(Disconnect).await.map_err(write_error)?;
+                Err(ConnectionError::Disconnect)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn test_eventloop() -> EventLoop {
+        EventLoop::new(MqttOptions::new("test", "localhost", 1883), 10)
+    }
+
+    #[tokio::test]
+    async fn handle_request_rejects_oversized_publish() {
+        let mut eventloop = test_eventloop();
+        let mut publish = Publish::new("topic", QoS::AtLeastOnce, vec![0u8; MAX_PAYLOAD_SIZE + 1]);
+        publish.pkid = 1;
+
+        let err = eventloop
+            .handle_request(Request::Publish(publish))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            ConnectionError::Client(ProtocolError::PayloadTooLong(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn handle_request_rejects_oversized_publish_with_ack_and_notifies_ack_future() {
+        let mut eventloop = test_eventloop();
+        let mut publish = Publish::new("topic", QoS::AtLeastOnce, vec![0u8; MAX_PAYLOAD_SIZE + 1]);
+        publish.pkid = 1;
+        let (tx, mut rx) = oneshot::channel();
+
+        let err = eventloop
+            .handle_request(Request::PublishWithAck(publish, tx))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err,
+            ConnectionError::Client(ProtocolError::PayloadTooLong(_))
+        ));
+        match rx.try_recv() {
+            Ok(Err(ProtocolError::PayloadTooLong(_))) => {}
+            other => panic!("expected Err(PayloadTooLong), got {:?}", other),
+        }
+    }
+}
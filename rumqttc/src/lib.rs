@@ -0,0 +1,35 @@
+#[macro_use]
+extern crate log;
+
+mod eventloop;
+mod mqttoptions;
+mod network;
+mod state;
+
+pub mod client;
+
+pub use mqtt4bytes::*;
+
+use tokio::sync::oneshot;
+
+pub use eventloop::{ConnectError, ConnectionError, Event, EventLoop, Outgoing, ProtocolError};
+pub use mqttoptions::MqttOptions;
+pub use state::MqttState;
+
+/// Requests sent by the client to the eventloop, one per MQTT operation.
+/// The eventloop drains these in order and turns them into wire packets.
+///
+/// The `WithAck` variants carry a pre-assigned pkid (set on the inner
+/// packet) and a `oneshot::Sender` that the eventloop fires once the
+/// matching PUBACK/PUBCOMP/SUBACK comes back from the broker, or with
+/// `Err` if the eventloop rejects the request (e.g. an oversized payload)
+/// before it's ever written to the network.
+#[derive(Debug)]
+pub enum Request {
+    Publish(Publish),
+    PublishWithAck(Publish, oneshot::Sender<Result<(), ProtocolError>>),
+    Subscribe(Subscribe),
+    SubscribeWithAck(Subscribe, oneshot::Sender<Result<(), ProtocolError>>),
+    Unsubscribe(Unsubscribe),
+    Disconnect,
+}
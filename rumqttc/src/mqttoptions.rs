@@ -0,0 +1,83 @@
+//! Options to configure the behaviour of the mqtt connection
+
+use std::time::Duration;
+
+use mqtt4bytes::LastWill;
+
+/// Options to configure the behaviour of mqtt connection
+#[derive(Clone, Debug)]
+pub struct MqttOptions {
+    client_id: String,
+    broker_addr: String,
+    port: u16,
+    keep_alive: Duration,
+    clean_session: bool,
+    credentials: Option<(String, String)>,
+    last_will: Option<LastWill>,
+}
+
+impl MqttOptions {
+    /// Create an `MqttOptions` object with default parameters for the broker at `host:port`
+    pub fn new<S: Into<String>, T: Into<String>>(id: S, host: T, port: u16) -> MqttOptions {
+        MqttOptions {
+            client_id: id.into(),
+            broker_addr: host.into(),
+            port,
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            credentials: None,
+            last_will: None,
+        }
+    }
+
+    pub fn client_id(&self) -> String {
+        self.client_id.clone()
+    }
+
+    pub fn broker_address(&self) -> (String, u16) {
+        (self.broker_addr.clone(), self.port)
+    }
+
+    pub fn set_keep_alive(&mut self, duration: Duration) -> &mut Self {
+        self.keep_alive = duration;
+        self
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    pub fn set_clean_session(&mut self, clean_session: bool) -> &mut Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn set_credentials<U: Into<String>, P: Into<String>>(
+        &mut self,
+        username: U,
+        password: P,
+    ) -> &mut Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn credentials(&self) -> Option<(String, String)> {
+        self.credentials.clone()
+    }
+
+    /// Set the Last-Will-and-Testament the broker publishes on our behalf
+    /// if the connection drops without a graceful DISCONNECT, e.g. a
+    /// `{"status":"Stopped"}` dead-man's-switch message on a status topic
+    pub fn set_last_will(&mut self, will: LastWill) -> &mut Self {
+        self.last_will = Some(will);
+        self
+    }
+
+    pub fn last_will(&self) -> Option<LastWill> {
+        self.last_will.clone()
+    }
+}
@@ -0,0 +1,52 @@
+//! Thin async wrapper around the TCP connection to the broker.
+
+use mqtt4bytes::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::MqttOptions;
+
+/// Wraps the socket to the broker and buffers partially read packets
+pub(crate) struct Network {
+    socket: TcpStream,
+    read_buf: Vec<u8>,
+}
+
+impl Network {
+    pub(crate) async fn connect(options: &MqttOptions) -> std::io::Result<Network> {
+        let (host, port) = options.broker_address();
+        let socket = TcpStream::connect((host.as_str(), port)).await?;
+        Ok(Network {
+            socket,
+            read_buf: Vec::with_capacity(10 * 1024),
+        })
+    }
+
+    /// Reads and returns the next complete incoming packet, reading more
+    /// bytes off the socket as needed.
+    pub(crate) async fn read(&mut self) -> std::io::Result<Packet> {
+        loop {
+            if let Ok(packet) = read(&mut self.read_buf, 10 * 1024) {
+                return Ok(packet);
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = self.socket.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "connection closed by broker",
+                ));
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    pub(crate) async fn write(&mut self, packet: impl mqtt4bytes::MqttWrite) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        packet
+            .write(&mut buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        self.socket.write_all(&buf).await
+    }
+}
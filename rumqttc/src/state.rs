@@ -0,0 +1,122 @@
+//! Tracks everything about an in-progress mqtt session that has to survive
+//! across individual `EventLoop::poll` calls (and, for packet identifiers,
+//! across reconnects).
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::oneshot;
+
+use crate::eventloop::ProtocolError;
+
+/// Hands out packet identifiers for QoS 1/2 publishes and subscribes.
+/// Shared (via `Arc`) between the `EventLoop`'s state and `AsyncClient` so
+/// that the client can stamp a pkid on a packet before it is ever sent.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PkidCounter(Arc<AtomicU16>);
+
+impl PkidCounter {
+    /// Next pkid, wrapping but skipping 0 (reserved by the spec as "no pkid")
+    pub(crate) fn next(&self) -> u16 {
+        loop {
+            let pkid = self.0.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            if pkid != 0 {
+                return pkid;
+            }
+        }
+    }
+}
+
+/// State of the mqtt connection, independent of the network transport
+pub struct MqttState {
+    pub(crate) pkids: PkidCounter,
+    /// Waiters for a PUBACK/PUBCOMP/SUBACK, keyed by the pkid of the packet
+    /// that was sent out.
+    pub(crate) inflight_acks: HashMap<u16, oneshot::Sender<Result<(), ProtocolError>>>,
+}
+
+impl MqttState {
+    pub fn new() -> MqttState {
+        MqttState {
+            pkids: PkidCounter::default(),
+            inflight_acks: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn register_ack(
+        &mut self,
+        pkid: u16,
+        notify: oneshot::Sender<Result<(), ProtocolError>>,
+    ) {
+        self.inflight_acks.insert(pkid, notify);
+    }
+
+    /// Fire (and forget) the waiter registered for `pkid`, if any.
+    pub(crate) fn handle_ack(&mut self, pkid: u16) {
+        if let Some(notify) = self.inflight_acks.remove(&pkid) {
+            let _ = notify.send(Ok(()));
+        }
+    }
+
+    /// Packets left unacked when a connection drops are not republished
+    /// after a reconnect, so waiters registered against the old connection
+    /// would never fire. Drop them rather than let them dangle.
+    pub(crate) fn clear_acks(&mut self) {
+        self.inflight_acks.clear();
+    }
+}
+
+impl Default for MqttState {
+    fn default() -> Self {
+        MqttState::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkid_counter_never_returns_zero() {
+        let counter = PkidCounter::default();
+        for _ in 0..(u16::MAX as u32 + 10) {
+            assert_ne!(counter.next(), 0);
+        }
+    }
+
+    #[test]
+    fn handle_ack_fires_registered_waiter() {
+        let mut state = MqttState::new();
+        let (tx, mut rx) = oneshot::channel();
+        state.register_ack(1, tx);
+
+        state.handle_ack(1);
+
+        assert!(matches!(rx.try_recv(), Ok(Ok(()))));
+    }
+
+    #[test]
+    fn handle_ack_ignores_unregistered_pkid() {
+        let mut state = MqttState::new();
+        let (tx, mut rx) = oneshot::channel();
+        state.register_ack(1, tx);
+
+        state.handle_ack(2);
+
+        assert!(rx.try_recv().is_err());
+        assert!(state.inflight_acks.contains_key(&1));
+    }
+
+    #[test]
+    fn clear_acks_drops_waiters_without_firing_them() {
+        let mut state = MqttState::new();
+        let (tx, mut rx) = oneshot::channel();
+        state.register_ack(1, tx);
+
+        state.clear_acks();
+
+        assert!(rx.try_recv().is_err());
+        assert!(state.inflight_acks.is_empty());
+    }
+}